@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Range;
+
+use crate::dfa::Dfa;
+use crate::nfa::Nfa;
+use crate::range::{common_refinement, CharRange, Step};
+use crate::regex::{GroupId, Regex};
+
+// An epsilon transition crossed while entering or leaving a Regex::Capture
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum CaptureEvent {
+	Enter(GroupId),
+	Exit(GroupId),
+}
+
+// Builds an Nfa<u64, Char> via a direct Thompson construction (rather than
+// going through the per-node relabel_states() that regex_to_nfa uses),
+// so that the epsilon transitions entering and leaving a Capture group can
+// be tagged with the state numbering they end up with
+struct Builder<Char> {
+	next_state: u64,
+	transitions: HashMap<u64, BTreeMap<CharRange<Char>, HashSet<u64>>>,
+	epsilon_transitions: HashMap<u64, HashSet<u64>>,
+	tags: HashMap<(u64, u64), Vec<CaptureEvent>>,
+}
+
+impl<Char> Builder<Char>
+where
+	Char: Ord + Clone,
+{
+	fn new() -> Self {
+		Builder
+			{ next_state: 0
+			, transitions: HashMap::new()
+			, epsilon_transitions: HashMap::new()
+			, tags: HashMap::new()
+			}
+	}
+
+	fn fresh_state(&mut self) -> u64 {
+		let state = self.next_state;
+		self.next_state += 1;
+		state
+	}
+
+	fn add_epsilon(&mut self, from: u64, to: u64) {
+		self.epsilon_transitions.entry(from).or_default().insert(to);
+	}
+
+	fn add_tagged_epsilon(&mut self, from: u64, to: u64, event: CaptureEvent) {
+		self.add_epsilon(from, to);
+		self.tags.entry((from, to)).or_default().push(event);
+	}
+
+	fn add_transition(&mut self, from: u64, range: CharRange<Char>, to: u64) {
+		self.transitions.entry(from).or_default().entry(range).or_default().insert(to);
+	}
+
+	// Build a fragment for a regex, returning its start state and its
+	// accepting states
+	fn build(&mut self, regex: &Regex<Char>) -> (u64, HashSet<u64>)
+	where
+		Char: Step,
+	{
+		match regex {
+			Regex::Empty => {
+				(self.fresh_state(), HashSet::new())
+			}
+			Regex::Epsilon => {
+				let state = self.fresh_state();
+				(state, HashSet::from([state]))
+			}
+			Regex::Character(char) => {
+				let start = self.fresh_state();
+				let end = self.fresh_state();
+				self.add_transition(start, CharRange::single(char.clone()), end);
+				(start, HashSet::from([end]))
+			}
+			Regex::Range(range) => {
+				let start = self.fresh_state();
+				let end = self.fresh_state();
+				self.add_transition(start, range.clone(), end);
+				(start, HashSet::from([end]))
+			}
+			Regex::Concat(left, right) => {
+				let (left_start, left_accepting) = self.build(left);
+				let (right_start, right_accepting) = self.build(right);
+				for state in &left_accepting {
+					self.add_epsilon(*state, right_start);
+				}
+				(left_start, right_accepting)
+			}
+			Regex::Union(left, right) => {
+				let start = self.fresh_state();
+				let (left_start, left_accepting) = self.build(left);
+				let (right_start, right_accepting) = self.build(right);
+				self.add_epsilon(start, left_start);
+				self.add_epsilon(start, right_start);
+				(start, left_accepting.union(&right_accepting).cloned().collect())
+			}
+			Regex::Star(contents) => {
+				let start = self.fresh_state();
+				let (inner_start, inner_accepting) = self.build(contents);
+				self.add_epsilon(start, inner_start);
+				for state in &inner_accepting {
+					self.add_epsilon(*state, inner_start);
+				}
+				let mut accepting = inner_accepting;
+				accepting.insert(start);
+				(start, accepting)
+			}
+			Regex::Capture(id, contents) => {
+				let start = self.fresh_state();
+				let end = self.fresh_state();
+				let (inner_start, inner_accepting) = self.build(contents);
+				self.add_tagged_epsilon(start, inner_start, CaptureEvent::Enter(*id));
+				for state in &inner_accepting {
+					self.add_tagged_epsilon(*state, end, CaptureEvent::Exit(*id));
+				}
+				(start, HashSet::from([end]))
+			}
+		}
+	}
+}
+
+pub struct TaggedNfa<Char> {
+	pub nfa: Nfa<u64, Char>,
+	pub tags: HashMap<(u64, u64), Vec<CaptureEvent>>,
+}
+
+pub fn regex_to_tagged_nfa<Char>(regex: &Regex<Char>) -> TaggedNfa<Char>
+where
+	Char: Ord + Clone + Step,
+{
+	let mut builder : Builder<Char> = Builder::new();
+	let (start, accepting) = builder.build(regex);
+	let states : HashSet<u64> = (0..builder.next_state).collect();
+	TaggedNfa
+		{ nfa: Nfa
+			{ states
+			, start_state: start
+			, transitions: builder.transitions
+			, epsilon_transitions: builder.epsilon_transitions
+			, accepting
+			}
+		, tags: builder.tags
+		}
+}
+
+// Epsilon-close a set of states, recording the tagged events crossed along
+// the way
+fn close<Char>(
+	nfa: &Nfa<u64, Char>,
+	tags: &HashMap<(u64, u64), Vec<CaptureEvent>>,
+	start: HashSet<u64>,
+) -> (HashSet<u64>, Vec<CaptureEvent>) {
+	let mut result : HashSet<u64> = HashSet::new();
+	let mut crossed : Vec<CaptureEvent> = Vec::new();
+	let mut to_visit : VecDeque<u64> = start.into_iter().collect();
+	while let Some(state) = to_visit.pop_front() {
+		if result.contains(&state) { continue }
+		result.insert(state);
+		if let Some(neighbours) = nfa.epsilon_transitions.get(&state) {
+			for neighbour in neighbours {
+				if let Some(events) = tags.get(&(state, *neighbour)) {
+					crossed.extend(events.iter().cloned());
+				}
+				to_visit.push_back(*neighbour);
+			}
+		}
+	}
+	(result, crossed)
+}
+
+// A DFA paired with, for each of its transitions, the capture group events
+// that transition crosses; produced by running subset construction over a
+// TaggedNfa while tracking which tagged epsilon edges each step closes over
+pub struct CapturingDfa<Char> {
+	pub dfa: Dfa<BTreeSet<u64>, Char>,
+	pub transition_tags: BTreeMap<(BTreeSet<u64>, CharRange<Char>), Vec<CaptureEvent>>,
+	// Events crossed while epsilon-closing the start state, before any
+	// input has been consumed
+	pub start_tags: Vec<CaptureEvent>,
+}
+
+// universe is the full range of characters that may appear in the input; see
+// conversions::nfa_to_dfa for why the DFA needs to be total over it
+pub fn regex_to_capturing_dfa<Char>(regex: &Regex<Char>, universe: CharRange<Char>) -> CapturingDfa<Char>
+where
+	Char: Ord + Clone + Step,
+{
+	let tagged = regex_to_tagged_nfa(regex);
+	let nfa = &tagged.nfa;
+	let tags = &tagged.tags;
+
+	let (start_set, start_tags) = close(nfa, tags, HashSet::from([nfa.start_state]));
+	let start_state : BTreeSet<u64> = BTreeSet::from_iter(start_set);
+
+	let mut states : HashSet<BTreeSet<u64>> = HashSet::new();
+	let mut transitions : HashMap<BTreeSet<u64>, BTreeMap<CharRange<Char>, BTreeSet<u64>>> = HashMap::new();
+	let mut accepting : HashSet<BTreeSet<u64>> = HashSet::new();
+	let mut transition_tags : BTreeMap<(BTreeSet<u64>, CharRange<Char>), Vec<CaptureEvent>> = BTreeMap::new();
+	let mut to_explore : VecDeque<BTreeSet<u64>> = VecDeque::from([start_state.clone()]);
+	loop {
+		match to_explore.pop_back() {
+			None => { break }
+			Some(state) => {
+				if states.contains(&state) { continue }
+				states.insert(state.clone());
+				if state.iter().any(|s| nfa.accepting.contains(s)) {
+					accepting.insert(state.clone());
+				}
+				// Universe is refined in too, same as conversions::nfa_to_dfa,
+				// so any part of it this state has no edge for still gets one,
+				// to the empty subset state, instead of the DFA being partial
+				let refinement = common_refinement(
+					std::iter::once(universe.clone())
+						.chain(
+							state
+								.iter()
+								.filter_map(|s| nfa.transitions.get(s))
+								.flat_map(|ranges| ranges.keys().cloned())
+							)
+					);
+				let mut out : BTreeMap<CharRange<Char>, BTreeSet<u64>> = BTreeMap::new();
+				for range in refinement {
+					let representative = range.start.clone();
+					let mut immediate : HashSet<u64> = HashSet::new();
+					for input in &state {
+						if let Some(output) = nfa.transition(input, &representative) {
+							immediate.extend(output.iter().cloned());
+						}
+					}
+					let (closed, crossed) = close(nfa, tags, immediate);
+					let target = BTreeSet::from_iter(closed);
+					transition_tags.insert((state.clone(), range.clone()), crossed);
+					out.insert(range, target.clone());
+					to_explore.push_back(target);
+				}
+				transitions.insert(state, out);
+			}
+		}
+	}
+
+	CapturingDfa
+		{ dfa: Dfa { states, start_state, transitions, accepting }
+		, transition_tags
+		, start_tags
+		}
+}
+
+// Apply the events crossed by one step to the open/closed group spans
+fn apply_events(
+	events: &[CaptureEvent],
+	position: usize,
+	open: &mut HashMap<GroupId, Vec<usize>>,
+	result: &mut HashMap<GroupId, Vec<Range<usize>>>,
+) {
+	for event in events {
+		match event {
+			CaptureEvent::Enter(id) => {
+				open.entry(*id).or_default().push(position);
+			}
+			CaptureEvent::Exit(id) => {
+				if let Some(start) = open.entry(*id).or_default().pop() {
+					result.entry(*id).or_default().push(start..position);
+				}
+			}
+		}
+	}
+}
+
+impl<Char> CapturingDfa<Char>
+where
+	Char: Ord + Clone,
+{
+	// Run input through the DFA and, if it's accepted, report the index
+	// ranges of the input matched by each capture group. Returns None if the
+	// input is not accepted, since the spans collected along a rejecting run
+	// don't describe a match
+	pub fn extract(&self, input: &[Char]) -> Option<HashMap<GroupId, Vec<Range<usize>>>> {
+		let mut result : HashMap<GroupId, Vec<Range<usize>>> = HashMap::new();
+		let mut open : HashMap<GroupId, Vec<usize>> = HashMap::new();
+		apply_events(&self.start_tags, 0, &mut open, &mut result);
+		let mut state = &self.dfa.start_state;
+		for (i, char) in input.iter().enumerate() {
+			let ranges = self.dfa.transitions.get(state).expect("Invalid capturing DFA");
+			let (range, target) = ranges
+				.iter()
+				.find(|(range, _)| range.contains(char))
+				.expect("No transition for character");
+			if let Some(events) = self.transition_tags.get(&(state.clone(), range.clone())) {
+				apply_events(events, i + 1, &mut open, &mut result);
+			}
+			state = target;
+		}
+		if !self.dfa.accepting.contains(state) { return None }
+		Some(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lowercase() -> CharRange<char> {
+		CharRange::new('a', '{')
+	}
+
+	#[test]
+	// A single matched span is still a Vec<Range<usize>>, not a Vec<usize>;
+	// clippy's suggestion for a one-element Range literal doesn't apply here
+	#[allow(clippy::single_range_in_vec_init)]
+	fn extract_reports_the_capture_span_on_a_match() {
+		let regex = Regex::Capture(0, Box::new(Regex::Character('a')));
+		let dfa = regex_to_capturing_dfa(&regex, lowercase());
+		let result = dfa.extract(&['a']).expect("'a' should match");
+		assert_eq!(result.get(&0), Some(&vec![0..1]));
+	}
+
+	// Regression test for the chunk1-6 completeness bug: a character within
+	// the declared universe but not covered by the pattern must not panic
+	#[test]
+	fn extract_rejects_without_panicking_on_uncovered_input() {
+		let regex = Regex::Capture(0, Box::new(Regex::Character('a')));
+		let dfa = regex_to_capturing_dfa(&regex, lowercase());
+		assert_eq!(dfa.extract(&['b']), None);
+	}
+
+	// A run that ends in a non-accepting state must report no match at all,
+	// not whatever spans were collected along the way
+	#[test]
+	fn extract_returns_none_on_non_accepting_termination() {
+		let regex = Regex::Capture(0, Box::new(Regex::Character('a')));
+		let dfa = regex_to_capturing_dfa(&regex, lowercase());
+		assert_eq!(dfa.extract(&['a', 'a']), None);
+	}
+}
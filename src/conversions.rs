@@ -1,37 +1,47 @@
-use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 
 use crate::dfa::Dfa;
 use crate::nfa::Nfa;
+use crate::range::{common_refinement, Bound, CharRange, Step};
 use crate::regex::Regex;
 
 pub fn dfa_to_nfa<State, Char>(dfa: &Dfa<State, Char>) -> Nfa<State, Char>
 where
 	State: Eq + Hash + Clone,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
 	Nfa
 		{ states : dfa.states.clone()
-		, alphabet: dfa.alphabet.clone()
 		, start_state: dfa.start_state.clone()
 		, transitions:
 			dfa
 				.transitions
 				.iter()
 				.map(
-					|(key, val)|
-					(key.clone(), HashSet::from([val.clone()]))
+					|(state, ranges)|
+					(
+						state.clone(),
+						ranges
+							.iter()
+							.map(|(range, target)| (range.clone(), HashSet::from([target.clone()])))
+							.collect::<BTreeMap<CharRange<Char>, HashSet<State>>>()
+					)
 				)
-				.collect::<HashMap<(State, Char), HashSet<State>>>()
+				.collect::<HashMap<State, BTreeMap<CharRange<Char>, HashSet<State>>>>()
 		, epsilon_transitions: HashMap::new()
 		, accepting: dfa.accepting.clone()
 		}
 }
 
-pub fn nfa_to_dfa<State, Char>(nfa: &Nfa<State, Char>) -> Dfa<BTreeSet<State>, Char>
+// universe is the full range of characters that may appear in the input;
+// the resulting DFA is total over it, with any subset state that has no
+// outgoing edge for part of the universe routed to the empty subset state,
+// which self-loops over the whole universe and accepts nothing
+pub fn nfa_to_dfa<State, Char>(nfa: &Nfa<State, Char>, universe: CharRange<Char>) -> Dfa<BTreeSet<State>, Char>
 where
 	State: Ord + Hash + Clone + 'static,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
 	let mut states : HashSet<BTreeSet<State>> = HashSet::new();
 	let start_state : BTreeSet<State>
@@ -39,7 +49,7 @@ where
 			nfa
 				.epsilon_closure(Box::new([nfa.start_state.clone()].into_iter()))
 			);
-	let mut transitions : HashMap<(BTreeSet<State>, Char), BTreeSet<State>>
+	let mut transitions : HashMap<BTreeSet<State>, BTreeMap<CharRange<Char>, BTreeSet<State>>>
 		= HashMap::new();
 	let mut accepting : HashSet<BTreeSet<State>> = HashSet::new();
 	let mut to_explore : VecDeque<BTreeSet<State>>
@@ -56,10 +66,27 @@ where
 						break
 					}
 				}
-				for char in &nfa.alphabet {
+				// Every outgoing edge from a member of this subset state may
+				// only partially overlap with another member's edge, so split
+				// them all into their common refinement before following them.
+				// The universe is refined in too, so any part of it none of the
+				// members cover still gets an edge, to the empty subset state,
+				// rather than the DFA being partial there
+				let refinement = common_refinement(
+					std::iter::once(universe.clone())
+						.chain(
+							state
+								.iter()
+								.filter_map(|s| nfa.transitions.get(s))
+								.flat_map(|ranges| ranges.keys().cloned())
+						)
+					);
+				let mut out : BTreeMap<CharRange<Char>, BTreeSet<State>> = BTreeMap::new();
+				for range in refinement {
+					let representative = range.start.clone();
 					let mut target: BTreeSet<State> = BTreeSet::new();
 					for input in &state {
-						match nfa.transition(input, char) {
+						match nfa.transition(input, &representative) {
 							None => {}
 							Some(output) => {
 								target.extend(output.clone());
@@ -69,50 +96,338 @@ where
 					let actual_target = BTreeSet::from_iter(
 						nfa.epsilon_closure(Box::new(target.into_iter()))
 					);
-					transitions.insert((state.clone(), char.clone()), actual_target.clone());
+					out.insert(range, actual_target.clone());
 					to_explore.push_back(actual_target);
 				}
+				transitions.insert(state, out);
 			}
 		}
 	}
 	Dfa
 		{ states
-		, alphabet: nfa.alphabet.clone()
 		, start_state
 		, transitions
 		, accepting
 		}
 }
 
-pub fn regex_to_nfa<Char>(regex: &Regex<Char>, alphabet: HashSet<Char>) -> Nfa<u64, Char>
+pub fn regex_to_nfa<Char>(regex: &Regex<Char>) -> Nfa<u64, Char>
 where
-	Char: Eq + Hash + Clone
+	Char: Ord + Clone + Step,
 {
 	match regex {
-		Regex::Empty => { Nfa::empty(alphabet).relabel_states() }
-		Regex::Epsilon => { Nfa::epsilon(alphabet).relabel_states() }
+		Regex::Empty => { crate::nfa::empty().relabel_states() }
+		Regex::Epsilon => { crate::nfa::epsilon().relabel_states() }
 		Regex::Character(char) =>
-			{ Nfa::character(alphabet, char.clone()).relabel_states() }
+			{ crate::nfa::character(char.clone()).relabel_states() }
+		Regex::Range(range) =>
+			{ crate::nfa::range(range.clone()).relabel_states() }
 		Regex::Concat(left, right) => {
-			let left_nfa = regex_to_nfa(left, alphabet.clone());
-			let right_nfa = regex_to_nfa(right, alphabet);
-			Nfa::concatenation(&left_nfa, &right_nfa).relabel_states()
+			let left_nfa = regex_to_nfa(left);
+			let right_nfa = regex_to_nfa(right);
+			crate::nfa::concatenation(&left_nfa, &right_nfa).relabel_states()
 		}
 		Regex::Union(left, right) => {
-			let left_nfa = regex_to_nfa(left, alphabet.clone());
-			let right_nfa = regex_to_nfa(right, alphabet);
-			Nfa::union(&left_nfa, &right_nfa).relabel_states()
+			let left_nfa = regex_to_nfa(left);
+			let right_nfa = regex_to_nfa(right);
+			crate::nfa::union(&left_nfa, &right_nfa).relabel_states()
 		}
 		Regex::Star(contents) => {
-			let contents_nfa = regex_to_nfa(contents, alphabet);
+			let contents_nfa = regex_to_nfa(contents);
 			contents_nfa.star().relabel_states()
 		}
+		// Capture groups only affect crate::capture's tagged construction;
+		// a plain Nfa has no way to record them, so just match the contents
+		Regex::Capture(_, contents) => { regex_to_nfa(contents) }
+	}
+}
+
+pub fn regex_to_dfa<Char>(regex: &Regex<Char>, universe: CharRange<Char>) -> Dfa<u64, Char>
+where
+	Char: Ord + Clone + Step,
+{
+	nfa_to_dfa(&regex_to_nfa(regex), universe).relabel_states()
+}
+
+// Which ends of a match are required to line up with the ends of the input
+pub enum Anchoring {
+	// The whole input must match, like the plain conversions above
+	Anchored,
+	// The match may end before the end of the input
+	LeftAnchored,
+	// The match may start after the start of the input
+	RightAnchored,
+	// The match may be a substring anywhere in the input
+	Unanchored,
+}
+
+// Apply the requested anchoring to an NFA before it is handed to nfa_to_dfa,
+// given the range of characters that can appear in the input
+pub fn unanchored<State, Char>(nfa: &Nfa<State, Char>, anchoring: Anchoring, universe: CharRange<Char>) -> Nfa<u64, Char>
+where
+	State: Ord + Hash + Clone + 'static,
+	Char: Ord + Clone,
+{
+	match anchoring {
+		Anchoring::Anchored => nfa.relabel_states(),
+		Anchoring::LeftAnchored => nfa.unanchor_end(universe).relabel_states(),
+		Anchoring::RightAnchored => nfa.unanchor_start(universe).relabel_states(),
+		Anchoring::Unanchored =>
+			nfa.unanchor_start(universe.clone()).unanchor_end(universe).relabel_states(),
+	}
+}
+
+// A node of the generalized NFA used by dfa_to_regex: the DFA's own states,
+// plus a fresh start and a fresh accept node
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum GnfaState<State> {
+	Start,
+	Accept,
+	Old(State),
+}
+
+// A half-open range covers finitely many characters, so expand it back into
+// a union of the individual characters it matches
+fn range_to_regex<Char>(range: &CharRange<Char>) -> Regex<Char>
+where
+	Char: Step,
+{
+	let mut members : Vec<Regex<Char>> = Vec::new();
+	let mut current = range.start.clone();
+	while Bound::Value(current.clone()) < range.end {
+		match current.succ() {
+			Bound::Value(next) => {
+				members.push(Regex::Character(current));
+				current = next;
+			}
+			// current is the last representable Char, so it's the final
+			// member of the range regardless of what range.end says
+			Bound::PastMax => {
+				members.push(Regex::Character(current));
+				break
+			}
+		}
+	}
+	members
+		.into_iter()
+		.reduce(|left, right| Regex::Union(Box::new(left), Box::new(right)))
+		.unwrap_or(Regex::Empty)
+}
+
+// Union a new edge label into the GNFA, dropping edges that carry no
+// strings at all instead of cluttering the graph with Regex::Empty labels
+fn add_edge<State, Char>(
+	edges: &mut HashMap<(GnfaState<State>, GnfaState<State>), Regex<Char>>,
+	from: GnfaState<State>,
+	to: GnfaState<State>,
+	label: Regex<Char>,
+)
+where
+	State: Eq + Hash + Clone,
+{
+	if matches!(label, Regex::Empty) { return }
+	match edges.remove(&(from.clone(), to.clone())) {
+		None => { edges.insert((from, to), label); }
+		Some(existing) => {
+			edges.insert((from, to), Regex::Union(Box::new(existing), Box::new(label)));
+		}
+	}
+}
+
+// Convert a DFA back into a regex that recognises the same language, using
+// the generalized-NFA state elimination algorithm
+pub fn dfa_to_regex<State, Char>(dfa: &Dfa<State, Char>) -> Regex<Char>
+where
+	State: Eq + Hash + Clone,
+	Char: Ord + Clone + Step,
+{
+	let mut edges : HashMap<(GnfaState<State>, GnfaState<State>), Regex<Char>> = HashMap::new();
+	add_edge(&mut edges, GnfaState::Start, GnfaState::Old(dfa.start_state.clone()), Regex::Epsilon);
+	for state in &dfa.accepting {
+		add_edge(&mut edges, GnfaState::Old(state.clone()), GnfaState::Accept, Regex::Epsilon);
+	}
+	for (state, ranges) in &dfa.transitions {
+		for (range, target) in ranges {
+			add_edge(
+				&mut edges,
+				GnfaState::Old(state.clone()),
+				GnfaState::Old(target.clone()),
+				range_to_regex(range)
+				);
+		}
 	}
+
+	for state in dfa.states.iter().cloned() {
+		let q = GnfaState::Old(state);
+		// Regex::Empty here, not Epsilon: Star(Empty) and Star(Epsilon) both
+		// denote only the empty string under Kleene-star semantics, but
+		// Star(Epsilon) makes parse_string recurse into itself with the same
+		// unconsumed input forever, since Epsilon always matches that input
+		let self_loop = edges.remove(&(q.clone(), q.clone())).unwrap_or(Regex::Empty);
+		let incoming_keys : Vec<(GnfaState<State>, GnfaState<State>)>
+			= edges.keys().filter(|(_, to)| *to == q).cloned().collect();
+		let incoming : Vec<(GnfaState<State>, Regex<Char>)>
+			= incoming_keys
+				.into_iter()
+				.map(|key| { let label = edges.remove(&key).expect("Just found this key"); (key.0, label) })
+				.collect();
+		let outgoing_keys : Vec<(GnfaState<State>, GnfaState<State>)>
+			= edges.keys().filter(|(from, _)| *from == q).cloned().collect();
+		let outgoing : Vec<(GnfaState<State>, Regex<Char>)>
+			= outgoing_keys
+				.into_iter()
+				.map(|key| { let label = edges.remove(&key).expect("Just found this key"); (key.1, label) })
+				.collect();
+		for (i, r_iq) in &incoming {
+			for (j, r_qj) in &outgoing {
+				let bypass = Regex::Concat(
+					Box::new(r_iq.clone()),
+					Box::new(Regex::Concat(
+						Box::new(Regex::Star(Box::new(self_loop.clone()))),
+						Box::new(r_qj.clone())
+						))
+					);
+				add_edge(&mut edges, i.clone(), j.clone(), bypass);
+			}
+		}
+	}
+
+	edges.remove(&(GnfaState::Start, GnfaState::Accept)).unwrap_or(Regex::Empty)
 }
 
-pub fn regex_to_dfa<Char>(regex: &Regex<Char>, alphabet: HashSet<Char>) -> Dfa<u64, Char>
+// Minimize a DFA using Brzozowski's double reversal method: reversing a DFA
+// and determinizing it once removes unreachable states, and doing that
+// twice also removes equivalent-state redundancy, leaving the unique
+// minimal DFA
+pub fn minimize<State, Char>(dfa: &Dfa<State, Char>, universe: CharRange<Char>) -> Dfa<u64, Char>
 where
-	Char: Eq + Hash + Clone
+	State: Ord + Hash + Clone + 'static,
+	Char: Ord + Clone,
 {
-	nfa_to_dfa(&regex_to_nfa(regex, alphabet)).relabel_states()
-}
\ No newline at end of file
+	let once = nfa_to_dfa(&dfa_to_nfa(dfa).reverse(), universe.clone()).relabel_states();
+	nfa_to_dfa(&dfa_to_nfa(&once).reverse(), universe).relabel_states()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lowercase() -> CharRange<char> {
+		CharRange::new('a', '{')
+	}
+
+	// Dfa::transition is private to dfa.rs and Dfa::parse_string doesn't
+	// report acceptance, so walk the DFA by hand here the same way they do
+	fn dfa_accepts<State, Char>(dfa: &Dfa<State, Char>, string: &[Char]) -> bool
+	where
+		State: Eq + Hash + Clone,
+		Char: Ord + Clone,
+	{
+		let mut state = &dfa.start_state;
+		for char in string {
+			let ranges = dfa.transitions.get(state).expect("Invalid DFA");
+			state = ranges
+				.iter()
+				.find(|(range, _)| range.contains(char))
+				.map(|(_, target)| target)
+				.expect("No transition for character");
+		}
+		dfa.accepting.contains(state)
+	}
+
+	// A DFA for a single-character NFA must be total over the declared
+	// universe: any character not covered by the pattern's own transitions
+	// still needs an edge, to the dead/empty subset state, instead of
+	// nfa_to_dfa leaving it partial there
+	#[test]
+	fn nfa_to_dfa_is_total_over_the_universe() {
+		let dfa = regex_to_dfa(&Regex::Character('a'), lowercase());
+		assert!(dfa_accepts(&dfa, &['a']));
+		assert!(!dfa_accepts(&dfa, &['b']));
+	}
+
+	// Regression test: CharRange::through_max lets the universe actually
+	// cover the whole of Unicode, including char::MAX itself, which used to
+	// panic trying to call succ() on a char with no successor
+	#[test]
+	fn regex_to_dfa_handles_the_full_unicode_universe() {
+		let dfa = regex_to_dfa(&Regex::Character(char::MAX), CharRange::through_max('\0'));
+		assert!(dfa_accepts(&dfa, &[char::MAX]));
+		assert!(!dfa_accepts(&dfa, &['a']));
+	}
+
+	// Regression test for the chunk1-4 GNFA elimination bug: a state with no
+	// self-loop used to default its bypass edge to Star(Epsilon), which made
+	// Regex::parse_string recurse into itself with the same unconsumed input
+	// forever. Regex::parse_string's own Star arm never accepts the
+	// zero-repetition case for non-nullable contents, so it can't be used to
+	// check the round trip directly; instead rebuild a DFA from the
+	// round-tripped regex and compare its accepted language against the
+	// original DFA via dfa_accepts.
+	#[test]
+	fn dfa_to_regex_round_trips_to_the_same_language() {
+		let dfa = regex_to_dfa(&Regex::Character('a'), lowercase());
+		let regex = dfa_to_regex(&dfa);
+		let roundtripped = regex_to_dfa(&regex, lowercase());
+		assert!(dfa_accepts(&roundtripped, &['a']));
+		assert!(!dfa_accepts(&roundtripped, &['b']));
+		assert!(!dfa_accepts(&roundtripped, &[]));
+		assert!(!dfa_accepts(&roundtripped, &['a', 'a']));
+	}
+
+	// Brzozowski double-reversal minimization must still recognise the same
+	// language as the DFA it started from
+	#[test]
+	fn minimize_preserves_the_language() {
+		let dfa = regex_to_dfa(&Regex::Character('a'), lowercase());
+		let minimized = minimize(&dfa, lowercase());
+		assert!(dfa_accepts(&minimized, &['a']));
+		assert!(!dfa_accepts(&minimized, &['b']));
+	}
+
+	// "ab", built from a plain Concat regex so every anchoring variant is
+	// exercised against the same pattern
+	fn ab_nfa() -> Nfa<u64, char> {
+		regex_to_nfa(&Regex::Concat(
+			Box::new(Regex::Character('a')),
+			Box::new(Regex::Character('b')),
+			))
+	}
+
+	#[test]
+	fn anchored_only_matches_the_whole_input() {
+		let dfa = nfa_to_dfa(&unanchored(&ab_nfa(), Anchoring::Anchored, lowercase()), lowercase());
+		assert!(dfa_accepts(&dfa, &['a', 'b']));
+		assert!(!dfa_accepts(&dfa, &['a', 'b', 'c']));
+		assert!(!dfa_accepts(&dfa, &['x', 'a', 'b']));
+	}
+
+	// LeftAnchored: the match must start at the start of the input, but may
+	// end before the input does, so "ab" followed by anything still matches
+	#[test]
+	fn left_anchored_matches_a_prefix() {
+		let dfa = nfa_to_dfa(&unanchored(&ab_nfa(), Anchoring::LeftAnchored, lowercase()), lowercase());
+		assert!(dfa_accepts(&dfa, &['a', 'b']));
+		assert!(dfa_accepts(&dfa, &['a', 'b', 'c']));
+		assert!(!dfa_accepts(&dfa, &['x', 'a', 'b']));
+	}
+
+	// RightAnchored: the match must end at the end of the input, but may
+	// start after the input does, so anything followed by "ab" still matches
+	#[test]
+	fn right_anchored_matches_a_suffix() {
+		let dfa = nfa_to_dfa(&unanchored(&ab_nfa(), Anchoring::RightAnchored, lowercase()), lowercase());
+		assert!(dfa_accepts(&dfa, &['a', 'b']));
+		assert!(dfa_accepts(&dfa, &['x', 'a', 'b']));
+		assert!(!dfa_accepts(&dfa, &['a', 'b', 'c']));
+	}
+
+	// Unanchored: "ab" may appear as a substring anywhere in the input
+	#[test]
+	fn unanchored_matches_a_substring_anywhere() {
+		let dfa = nfa_to_dfa(&unanchored(&ab_nfa(), Anchoring::Unanchored, lowercase()), lowercase());
+		assert!(dfa_accepts(&dfa, &['a', 'b']));
+		assert!(dfa_accepts(&dfa, &['x', 'a', 'b', 'y']));
+		assert!(!dfa_accepts(&dfa, &['a', 'c', 'b']));
+	}
+}
@@ -1,38 +1,50 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
+use map_macro::hash_map;
+
+use crate::range::{common_refinement, Bound, CharRange};
+
+// A state's outgoing edges, keyed by the disjoint character ranges that
+// follow them; shared by Dfa itself and by every construction below that
+// builds a fresh transition table over some relabelling of its states
+pub type Transitions<State, Char> = HashMap<State, BTreeMap<CharRange<Char>, State>>;
 
 pub struct Dfa<State, Char> {
 	pub states: HashSet<State>,
-	pub alphabet: HashSet<Char>,
 	pub start_state: State,
-	pub transitions: HashMap<(State, Char), State>,
+	pub transitions: Transitions<State, Char>,
 	pub accepting: HashSet<State>,
 }
 
 impl<State, Char> Dfa<State, Char>
 where
 	State: Eq + Hash + Clone,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
 	// Transition from one state to the next given a character
 	fn transition(&self, state: &State, char: &Char) -> &State {
 		self
 			.transitions
-			.get(&(state.clone(), char.clone()))
+			.get(state)
 			.expect("Invalid DFA")
+			.iter()
+			.find(|(range, _)| range.contains(char))
+			.map(|(_, target)| target)
+			.expect("No transition for character")
 	}
 
 	// Check the way a DFA is stored is valid
 	pub fn validate(&self) -> bool {
 		if ! self.states.contains(&self.start_state) { return false }
-		for state in &self.states {
-			for char in &self.alphabet {
-				match self.transitions.get(&(state.clone(), char.clone())) {
-					None => { return false }
-					Some(result) => {
-						if ! self.states.contains(result) { return false }
-					}
+		for (state, ranges) in &self.transitions {
+			if ! self.states.contains(state) { return false }
+			let mut prev_end : Option<&Bound<Char>> = None;
+			for (range, target) in ranges {
+				if let Some(end) = prev_end {
+					if Bound::Value(range.start.clone()) < *end { return false }
 				}
+				prev_end = Some(&range.end);
+				if ! self.states.contains(target) { return false }
 			}
 		}
 		for state in &self.accepting {
@@ -60,8 +72,10 @@ where
 				Some(state) => {
 					if result.contains(state) { continue }
 					result.insert(state.clone());
-					for char in &self.alphabet {
-						to_visit.push_front(self.transition(state, char));
+					if let Some(ranges) = self.transitions.get(state) {
+						for target in ranges.values() {
+							to_visit.push_front(target);
+						}
 					}
 				}
 			}
@@ -92,7 +106,6 @@ where
 		}
 		Dfa
 			{ states: self.states.clone()
-			, alphabet: self.alphabet.clone()
 			, start_state: self.start_state.clone()
 			, transitions: self.transitions.clone()
 			, accepting: non_accepting
@@ -111,19 +124,25 @@ where
 		let mut map_to_new : HashMap<State, u64> = HashMap::new();
 		// let mut map_to_old : HashMap<u64, State> = HashMap::new();
 		let mut states : HashSet<u64> = HashSet::new();
-		for (i, state) in (0_u64..).zip(self.reachable_states().into_iter()) {
+		for (i, state) in (0_u64..).zip(self.reachable_states()) {
 			map_to_new.insert(state.clone(), i);
 			// map_to_old.insert(i, state);
 			states.insert(i);
 		}
-		let mut transitions: HashMap<(u64, Char), u64> = HashMap::new();
-		for ((input, char), output) in &self.transitions {
-			match map_to_new.get(input) {
+		let mut transitions: Transitions<u64, Char> = HashMap::new();
+		for (state, ranges) in &self.transitions {
+			match map_to_new.get(state) {
 				None => {}
-				Some(new_input) => {
+				Some(new_state) => {
 					transitions.insert(
-						(*new_input, char.clone()),
-						*map_to_new.get(output).expect("Transition from reachable to unreachable state")
+						*new_state,
+						ranges
+							.iter()
+							.map(|(range, target)| (
+								range.clone(),
+								*map_to_new.get(target).expect("Transition from reachable to unreachable state")
+								))
+							.collect::<BTreeMap<CharRange<Char>, u64>>()
 					);
 				}
 			}
@@ -139,7 +158,6 @@ where
 		}
 		Dfa
 			{ states
-			, alphabet: self.alphabet.clone()
 			, start_state: *map_to_new.get(&self.start_state).expect("Start state is not reachable")
 			, transitions
 			, accepting
@@ -148,42 +166,34 @@ where
 }
 
 impl<Char> Dfa<(), Char>{
-	// Construct a DFA that recognises no strings
-	pub fn empty(alphabet: HashSet<Char>) -> Dfa<(), Char>
+	// Construct a DFA that recognises no strings, given the range of
+	// characters that can appear in the input
+	pub fn empty(universe: CharRange<Char>) -> Dfa<(), Char>
 	where
-		Char: Eq + Hash + Clone,
+		Char: Ord + Clone,
 	{
 		let mut states : HashSet<()> = HashSet::new();
 		states.insert(());
-		let mut transitions : HashMap<((), Char), ()> = HashMap::new();
-		for char in &alphabet {
-			transitions.insert(((), char.clone()), ());
-		}
 		Dfa
 			{ states
-			, alphabet
 			, start_state: ()
-			, transitions
+			, transitions: hash_map! { () => BTreeMap::from([(universe, ())]) }
 			, accepting: HashSet::new()
 			}
 	}
 
-	// Construct a DFA that recognises every string
-	pub fn complete(alphabet: HashSet<Char>) -> Dfa<(), Char>
+	// Construct a DFA that recognises every string, given the range of
+	// characters that can appear in the input
+	pub fn complete(universe: CharRange<Char>) -> Dfa<(), Char>
 	where
-		Char: Eq + Hash + Clone,
+		Char: Ord + Clone,
 	{
 		let mut states : HashSet<()> = HashSet::new();
 		states.insert(());
-		let mut transitions : HashMap<((), Char), ()> = HashMap::new();
-		for char in &alphabet {
-			transitions.insert(((), char.clone()), ());
-		}
 		Dfa
 			{ states: states.clone()
-			, alphabet
 			, start_state: ()
-			, transitions
+			, transitions: hash_map! { () => BTreeMap::from([(universe, ())]) }
 			, accepting: states
 			}
 	}
@@ -193,7 +203,7 @@ impl<State1, State2, Char> Dfa<(State1, State2), Char>
 where
 	State1: Eq + Hash + Clone,
 	State2: Eq + Hash + Clone,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
 	// Creates a DFA that simulates two other DFAs and accepts a string using
 	// a function and whether the two DFAs accept
@@ -201,23 +211,34 @@ where
 	pub fn product
 		(first: &Dfa<State1, Char>, second: &Dfa<State2, Char>, func: fn(bool, bool) -> bool)
 		-> Dfa<(State1, State2), Char> {
-		assert!(first.alphabet == second.alphabet, "Cannot product DFA with different alphabets");
 		let mut new_states : HashSet<(State1, State2)> = HashSet::new();
 		for state1 in &first.states {
 			for state2 in &second.states {
 				new_states.insert((state1.clone(), state2.clone()));
 			}
 		}
-		let mut new_transitions : HashMap<((State1, State2), Char), (State1, State2)> = HashMap::new();
+		let mut new_transitions : Transitions<(State1, State2), Char> = HashMap::new();
 		for (state1, state2) in &new_states {
-			for char in &first.alphabet {
-				new_transitions.insert(
-					((state1.clone(), state2.clone()), char.clone()),
-					( first.transition(state1, char).clone()
-					, second.transition(state2, char).clone()
-					)
+			let first_ranges = first.transitions.get(state1);
+			let second_ranges = second.transitions.get(state2);
+			let refinement = common_refinement(
+				first_ranges
+					.into_iter()
+					.flat_map(|ranges| ranges.keys().cloned())
+					.chain(
+						second_ranges
+							.into_iter()
+							.flat_map(|ranges| ranges.keys().cloned())
+						)
 				);
+			let mut out : BTreeMap<CharRange<Char>, (State1, State2)> = BTreeMap::new();
+			for range in refinement {
+				let representative = range.start.clone();
+				let target1 = first.transition(state1, &representative).clone();
+				let target2 = second.transition(state2, &representative).clone();
+				out.insert(range, (target1, target2));
 			}
+			new_transitions.insert((state1.clone(), state2.clone()), out);
 		}
 		let mut new_accepting : HashSet<(State1, State2)> = HashSet::new();
 		for (state, state2) in &new_states {
@@ -227,7 +248,6 @@ where
 		}
 		Dfa
 			{ states: new_states
-			, alphabet: first.alphabet.clone()
 			, start_state: (first.start_state.clone(), second.start_state.clone())
 			, transitions: new_transitions
 			, accepting: new_accepting
@@ -261,4 +281,4 @@ where
 	{
 		Dfa::product(first, second, |a, b| a != b)
 	}
-}
\ No newline at end of file
+}
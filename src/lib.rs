@@ -0,0 +1,6 @@
+pub mod capture;
+pub mod conversions;
+pub mod dfa;
+pub mod nfa;
+pub mod range;
+pub mod regex;
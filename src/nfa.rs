@@ -1,17 +1,23 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 use map_macro::hash_map;
 
+use crate::range::{common_refinement, Bound, CharRange, Step};
+
+// A state's outgoing edges, keyed by the disjoint character ranges that
+// follow them; shared by Nfa itself and by every construction below that
+// builds a fresh transition table over some relabelling of its states
+pub type Transitions<State, Char> = HashMap<State, BTreeMap<CharRange<Char>, HashSet<State>>>;
+
 pub struct Nfa<State, Char> {
 	pub states: HashSet<State>,
-	pub alphabet: HashSet<Char>,
 	pub start_state: State,
-	pub transitions: HashMap<(State, Char), HashSet<State>>,
+	pub transitions: Transitions<State, Char>,
 	pub epsilon_transitions: HashMap<State, HashSet<State>>,
 	pub accepting: HashSet<State>,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum StarState<State> {
 	Start,
 	Old(State),
@@ -20,27 +26,31 @@ pub enum StarState<State> {
 impl<State, Char> Nfa<State, Char>
 where
 	State: Eq + Hash + Clone + 'static,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
 	// Follow all transitions labelled by a character
 	pub fn transition(&self, state: &State, char: &Char) -> Option<&HashSet<State>> {
 		self
 			.transitions
-			.get(&(state.clone(), char.clone()))
+			.get(state)?
+			.iter()
+			.find(|(range, _)| range.contains(char))
+			.map(|(_, targets)| targets)
 	}
 
 	// Check the NFA representation is valid
 	pub fn validate(&self) -> bool {
 		if !self.states.contains(&self.start_state) { return false }
-		for state in &self.states {
-			for char in &self.alphabet {
-				match self.transition(state, char) {
-					None => {}
-					Some(result) => {
-						for out_state in result {
-							if !self.states.contains(out_state) { return false }
-						}
-					}
+		for (state, ranges) in &self.transitions {
+			if !self.states.contains(state) { return false }
+			let mut prev_end : Option<&Bound<Char>> = None;
+			for (range, targets) in ranges {
+				if let Some(end) = prev_end {
+					if Bound::Value(range.start.clone()) < *end { return false }
+				}
+				prev_end = Some(&range.end);
+				for out_state in targets {
+					if !self.states.contains(out_state) { return false }
 				}
 			}
 		}
@@ -97,13 +107,10 @@ where
 				Some(state) => {
 					if result.contains(&state) { continue }
 					result.insert(state.clone());
-					for char in &self.alphabet {
-						match self.transition(&state.clone(), char) {
-							None => {}
-							Some(neighbours) => {
-								for neighbour in neighbours {
-									to_visit.push_front(neighbour.clone());
-								}
+					if let Some(ranges) = self.transitions.get(&state) {
+						for targets in ranges.values() {
+							for neighbour in targets {
+								to_visit.push_front(neighbour.clone());
 							}
 						}
 					}
@@ -153,15 +160,20 @@ where
 		for state in &self.states {
 			states.insert(StarState::Old(state.clone()));
 		}
-		let mut transitions : HashMap<(StarState<State>, Char), HashSet<StarState<State>>>
-			= HashMap::new();
-		for ((state, char), result) in &self.transitions {
+		let mut transitions : Transitions<StarState<State>, Char> = HashMap::new();
+		for (state, ranges) in &self.transitions {
 			transitions.insert(
-				(StarState::Old(state.clone()), char.clone()),
-				result
+				StarState::Old(state.clone()),
+				ranges
 					.iter()
-					.map(|s| StarState::Old(s.clone()))
-					.collect::<HashSet<StarState<State>>>()
+					.map(|(range, targets)| (
+						range.clone(),
+						targets
+							.iter()
+							.map(|s| StarState::Old(s.clone()))
+							.collect::<HashSet<StarState<State>>>()
+						))
+					.collect::<BTreeMap<CharRange<Char>, HashSet<StarState<State>>>>()
 				);
 		}
 		let mut epsilon_transitions : HashMap<StarState<State>, HashSet<StarState<State>>>
@@ -189,7 +201,6 @@ where
 		}
 		Nfa
 			{ states
-			, alphabet: self.alphabet.clone()
 			, start_state: StarState::Start
 			, transitions
 			, epsilon_transitions
@@ -197,27 +208,184 @@ where
 		}
 }
 
+	// NFA that accepts any string with a suffix accepted by this NFA, by
+	// prepending a new start state with a self loop over the whole universe
+	// of characters and an epsilon transition into the old start state
+	pub fn unanchor_start(&self, universe: CharRange<Char>) -> Nfa<StarState<State>, Char> {
+		let mut states : HashSet<StarState<State>> = HashSet::from([StarState::Start]);
+		for state in &self.states {
+			states.insert(StarState::Old(state.clone()));
+		}
+		let mut transitions : Transitions<StarState<State>, Char> = HashMap::new();
+		for (state, ranges) in &self.transitions {
+			transitions.insert(
+				StarState::Old(state.clone()),
+				ranges
+					.iter()
+					.map(|(range, targets)| (
+						range.clone(),
+						targets
+							.iter()
+							.map(|s| StarState::Old(s.clone()))
+							.collect::<HashSet<StarState<State>>>()
+						))
+					.collect::<BTreeMap<CharRange<Char>, HashSet<StarState<State>>>>()
+				);
+		}
+		transitions.insert(
+			StarState::Start,
+			BTreeMap::from([(universe, HashSet::from([StarState::Start]))])
+			);
+		let mut epsilon_transitions : HashMap<StarState<State>, HashSet<StarState<State>>>
+			= hash_map!
+				{ StarState::Start => HashSet::from([StarState::Old(self.start_state.clone())])
+				};
+		for (state, result) in &self.epsilon_transitions {
+			epsilon_transitions.insert(
+				StarState::Old(state.clone()),
+				result
+					.iter()
+					.map(|s| StarState::Old(s.clone()))
+					.collect::<HashSet<StarState<State>>>()
+				);
+		}
+		let accepting : HashSet<StarState<State>>
+			= self.accepting.iter().map(|s| StarState::Old(s.clone())).collect();
+		Nfa
+			{ states
+			, start_state: StarState::Start
+			, transitions
+			, epsilon_transitions
+			, accepting
+			}
+	}
+
+	// NFA that accepts any string with a prefix accepted by this NFA, by
+	// giving every accepting state a self loop over the whole universe of
+	// characters so trailing input is consumed while remaining accepting
+	pub fn unanchor_end(&self, universe: CharRange<Char>) -> Nfa<State, Char> {
+		let mut transitions = self.transitions.clone();
+		for state in &self.accepting {
+			let existing = transitions.entry(state.clone()).or_default();
+			let refinement = common_refinement(
+				std::iter::once(universe.clone()).chain(existing.keys().cloned())
+				);
+			let mut new_ranges : BTreeMap<CharRange<Char>, HashSet<State>> = BTreeMap::new();
+			for range in refinement {
+				let representative = range.start.clone();
+				let mut targets : HashSet<State>
+					= existing
+						.iter()
+						.find(|(r, _)| r.contains(&representative))
+						.map(|(_, t)| t.clone())
+						.unwrap_or_default();
+				targets.insert(state.clone());
+				new_ranges.insert(range, targets);
+			}
+			*existing = new_ranges;
+		}
+		Nfa
+			{ states: self.states.clone()
+			, start_state: self.start_state.clone()
+			, transitions
+			, epsilon_transitions: self.epsilon_transitions.clone()
+			, accepting: self.accepting.clone()
+			}
+	}
+
+	// NFA that accepts the reverse of every string this one accepts, used by
+	// Brzozowski's minimization algorithm. Every transition and epsilon
+	// transition is flipped, the old accepting states become reachable from
+	// a new start state by epsilon transitions, and the old start state
+	// becomes the only accepting state
+	pub fn reverse(&self) -> Nfa<StarState<State>, Char> {
+		let mut states : HashSet<StarState<State>> = HashSet::from([StarState::Start]);
+		for state in &self.states {
+			states.insert(StarState::Old(state.clone()));
+		}
+		// Collect every (range, source) edge landing on each target state
+		// before merging them in, so that two different sources reaching the
+		// same target via overlapping-but-different ranges get refined into
+		// disjoint ranges instead of colliding as two keys in one BTreeMap
+		let mut incoming : HashMap<StarState<State>, Vec<(CharRange<Char>, State)>> = HashMap::new();
+		for (state, ranges) in &self.transitions {
+			for (range, targets) in ranges {
+				for target in targets {
+					incoming
+						.entry(StarState::Old(target.clone()))
+						.or_default()
+						.push((range.clone(), state.clone()));
+				}
+			}
+		}
+		let mut transitions : Transitions<StarState<State>, Char> = HashMap::new();
+		for (target, edges) in &incoming {
+			let refinement = common_refinement(edges.iter().map(|(range, _)| range.clone()));
+			let mut out : BTreeMap<CharRange<Char>, HashSet<StarState<State>>> = BTreeMap::new();
+			for range in refinement {
+				let representative = range.start.clone();
+				let sources : HashSet<StarState<State>>
+					= edges
+						.iter()
+						.filter(|(r, _)| r.contains(&representative))
+						.map(|(_, s)| StarState::Old(s.clone()))
+						.collect();
+				out.insert(range, sources);
+			}
+			transitions.insert(target.clone(), out);
+		}
+		let mut epsilon_transitions : HashMap<StarState<State>, HashSet<StarState<State>>>
+			= HashMap::new();
+		for (state, targets) in &self.epsilon_transitions {
+			for target in targets {
+				epsilon_transitions
+					.entry(StarState::Old(target.clone()))
+					.or_default()
+					.insert(StarState::Old(state.clone()));
+			}
+		}
+		epsilon_transitions
+			.entry(StarState::Start)
+			.or_default()
+			.extend(self.accepting.iter().map(|s| StarState::Old(s.clone())));
+		let accepting : HashSet<StarState<State>>
+			= HashSet::from([StarState::Old(self.start_state.clone())]);
+		Nfa
+			{ states
+			, start_state: StarState::Start
+			, transitions
+			, epsilon_transitions
+			, accepting
+			}
+	}
+
 	// Relabel the reachable states using integers
 	pub fn relabel_states(&self) -> Nfa<u64, Char> {
 		let mut map_to_new : HashMap<State, u64> = HashMap::new();
 		let mut map_to_old : HashMap<u64, State> = HashMap::new();
 		let mut states : HashSet<u64> = HashSet::new();
-		for (i, state) in (0_u64..).zip(self.reachable_states().into_iter()) {
+		for (i, state) in (0_u64..).zip(self.reachable_states()) {
 			map_to_new.insert(state.clone(), i);
 			map_to_old.insert(i, state);
 			states.insert(i);
 		}
-		let mut transitions : HashMap<(u64, Char), HashSet<u64>> = HashMap::new();
-		for ((state, char), target) in &self.transitions {
+		let mut transitions : HashMap<u64, BTreeMap<CharRange<Char>, HashSet<u64>>> = HashMap::new();
+		for (state, ranges) in &self.transitions {
 			match map_to_new.get(state) {
 				None => {},
 				Some(new_state) => {
 					transitions.insert(
-						(*new_state, char.clone()),
-						target
+						*new_state,
+						ranges
 							.iter()
-							.map(|s| *map_to_new.get(s).expect("Invalid NFA"))
-							.collect::<HashSet<u64>>()
+							.map(|(range, targets)| (
+								range.clone(),
+								targets
+									.iter()
+									.map(|s| *map_to_new.get(s).expect("Invalid NFA"))
+									.collect::<HashSet<u64>>()
+								))
+							.collect::<BTreeMap<CharRange<Char>, HashSet<u64>>>()
 					);
 				}
 			}
@@ -248,7 +416,6 @@ where
 		}
 		Nfa
 			{ states
-			, alphabet: self.alphabet.clone()
 			, start_state: *map_to_new.get(&self.start_state).expect("Invalid NFA")
 			, transitions
 			, epsilon_transitions
@@ -257,11 +424,39 @@ where
 	}
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Two different source states transitioning into the same target via
+	// overlapping-but-different ranges must not collide into one key of the
+	// reversed target's BTreeMap, or one of the two sources gets silently
+	// dropped
+	#[test]
+	fn reverse_keeps_both_sources_of_overlapping_incoming_ranges() {
+		let nfa : Nfa<u64, char> = Nfa
+			{ states: HashSet::from([0, 1, 2])
+			, start_state: 0
+			, transitions: hash_map! {
+				0 => BTreeMap::from([(CharRange::new('a', 'n'), HashSet::from([2]))]),
+				1 => BTreeMap::from([(CharRange::new('g', 'z'), HashSet::from([2]))]),
+				}
+			, epsilon_transitions: HashMap::new()
+			, accepting: HashSet::from([2])
+			};
+		let reversed = nfa.reverse();
+		assert!(reversed.validate());
+		let targets = reversed.transition(&StarState::Old(2), &'h').expect("some transition for 'h'");
+		assert_eq!(targets.len(), 2);
+		assert!(targets.contains(&StarState::Old(0)));
+		assert!(targets.contains(&StarState::Old(1)));
+	}
+}
+
 // NFA which accepts nothing
-pub fn empty<Char>(alphabet: HashSet<Char>) -> Nfa<(), Char> {
+pub fn empty<Char>() -> Nfa<(), Char> {
 	Nfa
 		{ states : HashSet::from([()])
-		, alphabet
 		, start_state: ()
 		, transitions: HashMap::new()
 		, epsilon_transitions: HashMap::new()
@@ -270,10 +465,9 @@ pub fn empty<Char>(alphabet: HashSet<Char>) -> Nfa<(), Char> {
 }
 
 // NFA which accepts the empty string only
-pub fn epsilon<Char>(alphabet: HashSet<Char>) -> Nfa<(), Char> {
+pub fn epsilon<Char>() -> Nfa<(), Char> {
 	Nfa
 		{ states: HashSet::from([()])
-		, alphabet
 		, start_state: ()
 		, transitions: HashMap::new()
 		, epsilon_transitions: HashMap::new()
@@ -282,17 +476,31 @@ pub fn epsilon<Char>(alphabet: HashSet<Char>) -> Nfa<(), Char> {
 }
 
 // NFA that accepts a single string, which is a single character
-pub fn character<Char>(alphabet: HashSet<Char>, char: Char) -> Nfa<bool, Char>
+pub fn character<Char>(char: Char) -> Nfa<bool, Char>
 where
-	Char: Eq + Hash,
+	Char: Ord + Clone + Step,
 {
-	assert!(alphabet.contains(&char), "Character should be in alphabet");
 	Nfa
 		{ states: HashSet::from([false, true])
-		, alphabet
 		, start_state: false
 		, transitions: hash_map! {
-			(false, char) => HashSet::from([true]),
+			false => BTreeMap::from([(CharRange::single(char), HashSet::from([true]))]),
+			}
+		, epsilon_transitions: HashMap::new()
+		, accepting: HashSet::from([true])
+		}
+}
+
+// NFA that accepts a single string, which is a single character in range
+pub fn range<Char>(range: CharRange<Char>) -> Nfa<bool, Char>
+where
+	Char: Ord + Clone,
+{
+	Nfa
+		{ states: HashSet::from([false, true])
+		, start_state: false
+		, transitions: hash_map! {
+			false => BTreeMap::from([(range, HashSet::from([true]))]),
 			}
 		, epsilon_transitions: HashMap::new()
 		, accepting: HashSet::from([true])
@@ -310,12 +518,10 @@ pub fn concatenation<State1, State2, Char>(left : &Nfa<State1, Char>, right : &N
 where
 	State1: Eq + Hash + Clone,
 	State2: Eq + Hash + Clone,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
-	assert!(left.alphabet == right.alphabet, "Alphabets must be equal!");
-
 	type StateSet<A, B> = HashSet<ConcatState<A, B>>;
-	type Transition<A, B, Char> = HashMap<(ConcatState<A, B>, Char), StateSet<A, B>>;
+	type Transition<A, B, Char> = HashMap<ConcatState<A, B>, BTreeMap<CharRange<Char>, StateSet<A, B>>>;
 
 	let mut states : StateSet<State1, State2> = HashSet::new();
 	for state in &left.states {
@@ -326,22 +532,34 @@ where
 	}
 	let mut transitions : Transition<State1, State2, Char>
 		= HashMap::new();
-	for ((state, char), result) in &left.transitions {
+	for (state, ranges) in &left.transitions {
 		transitions.insert(
-			(ConcatState::Left(state.clone()), char.clone()),
-			result
+			ConcatState::Left(state.clone()),
+			ranges
 				.iter()
-				.map(|s| ConcatState::Left(s.clone()))
-				.collect::<StateSet<State1, State2>>()
+				.map(|(range, targets)| (
+					range.clone(),
+					targets
+						.iter()
+						.map(|s| ConcatState::Left(s.clone()))
+						.collect::<StateSet<State1, State2>>()
+					))
+				.collect::<BTreeMap<CharRange<Char>, StateSet<State1, State2>>>()
 			);
 	}
-	for ((state, char), result) in &right.transitions {
+	for (state, ranges) in &right.transitions {
 		transitions.insert(
-			(ConcatState::Right(state.clone()), char.clone()),
-			result
+			ConcatState::Right(state.clone()),
+			ranges
 				.iter()
-				.map(|s| ConcatState::Right(s.clone()))
-				.collect::<StateSet<State1, State2>>()
+				.map(|(range, targets)| (
+					range.clone(),
+					targets
+						.iter()
+						.map(|s| ConcatState::Right(s.clone()))
+						.collect::<StateSet<State1, State2>>()
+					))
+				.collect::<BTreeMap<CharRange<Char>, StateSet<State1, State2>>>()
 			);
 	}
 	let mut epsilon_transitions : HashMap<ConcatState<State1, State2>, StateSet<State1, State2>>
@@ -372,7 +590,6 @@ where
 	}
 	Nfa
 		{ states
-		, alphabet: left.alphabet.clone()
 		, start_state: ConcatState::Left(left.start_state.clone())
 		, transitions
 		, epsilon_transitions
@@ -397,12 +614,10 @@ pub fn union<State1, State2, Char>(first : &Nfa<State1, Char>, second : &Nfa<Sta
 where
 	State1: Eq + Hash + Clone,
 	State2: Eq + Hash + Clone,
-	Char: Eq + Hash + Clone,
+	Char: Ord + Clone,
 {
-	assert!(first.alphabet == second.alphabet, "Alphabets must be equal!");
-
 	type StateSet<A, B> = HashSet<UnionState<A, B>>;
-	type Transition<A, B, Char> = HashMap<(UnionState<A, B>, Char), StateSet<A, B>>;
+	type Transition<A, B, Char> = HashMap<UnionState<A, B>, BTreeMap<CharRange<Char>, StateSet<A, B>>>;
 
 	let mut states : StateSet<State1, State2> = HashSet::from([UnionState::Start]);
 	for state in &first.states {
@@ -413,22 +628,34 @@ where
 	}
 	let mut transitions : Transition<State1, State2, Char>
 		= HashMap::new();
-	for ((state, char), result) in &first.transitions {
+	for (state, ranges) in &first.transitions {
 		transitions.insert(
-			(UnionState::First(state.clone()), char.clone()),
-			result
+			UnionState::First(state.clone()),
+			ranges
 				.iter()
-				.map(|s| UnionState::First(s.clone()))
-				.collect::<StateSet<State1, State2>>()
+				.map(|(range, targets)| (
+					range.clone(),
+					targets
+						.iter()
+						.map(|s| UnionState::First(s.clone()))
+						.collect::<StateSet<State1, State2>>()
+					))
+				.collect::<BTreeMap<CharRange<Char>, StateSet<State1, State2>>>()
 			);
 	}
-	for ((state, char), result) in &second.transitions {
+	for (state, ranges) in &second.transitions {
 		transitions.insert(
-			(UnionState::Second(state.clone()), char.clone()),
-			result
+			UnionState::Second(state.clone()),
+			ranges
 				.iter()
-				.map(|s| UnionState::Second(s.clone()))
-				.collect::<StateSet<State1, State2>>()
+				.map(|(range, targets)| (
+					range.clone(),
+					targets
+						.iter()
+						.map(|s| UnionState::Second(s.clone()))
+						.collect::<StateSet<State1, State2>>()
+					))
+				.collect::<BTreeMap<CharRange<Char>, StateSet<State1, State2>>>()
 			);
 	}
 	let mut epsilon_transitions : HashMap<UnionState<State1, State2>, StateSet<State1, State2>>
@@ -465,7 +692,6 @@ where
 	}
 	Nfa
 		{ states
-		, alphabet: first.alphabet.clone()
 		, start_state: UnionState::Start
 		, transitions
 		, epsilon_transitions
@@ -0,0 +1,171 @@
+use std::collections::BTreeSet;
+
+// Characters that have a well defined successor, needed to turn a single
+// character into a half-open range [char, char.succ())
+pub trait Step: Ord + Clone {
+	// Bound::PastMax means char was the last representable value, and so
+	// has no successor; callers get it instead of a value to step to
+	fn succ(&self) -> Bound<Self>
+	where
+		Self: Sized;
+}
+
+impl Step for char {
+	fn succ(&self) -> Bound<char> {
+		let next = *self as u32 + 1;
+		// char::from_u32 rejects the UTF-16 surrogate range D800..DFFF, which
+		// is not a valid char even though the code points either side of it are
+		let next = if next == 0xD800 { 0xE000 } else { next };
+		match char::from_u32(next) {
+			Some(char) => Bound::Value(char),
+			None => Bound::PastMax,
+		}
+	}
+}
+
+impl Step for u32 {
+	fn succ(&self) -> Bound<u32> {
+		match self.checked_add(1) {
+			Some(next) => Bound::Value(next),
+			None => Bound::PastMax,
+		}
+	}
+}
+
+// The exclusive end of a half-open range. Most ranges end at a concrete
+// value, but the last representable Char (e.g. char::MAX) has no successor
+// to use as an exclusive end, so PastMax stands in for "through the last
+// valid Char" without requiring Step::succ to produce a value that doesn't
+// exist. PastMax compares greater than every Value: it's declared last, and
+// derived ordering on an enum falls back to comparing variants by the order
+// they're declared in once the discriminants differ.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub enum Bound<Char> {
+	Value(Char),
+	PastMax,
+}
+
+// A half-open range of characters [start, end), used as the key of a
+// transition table so that one edge can cover many characters at once
+// instead of requiring one edge per character
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct CharRange<Char> {
+	pub start: Char,
+	pub end: Bound<Char>,
+}
+
+impl<Char: Ord + Clone> CharRange<Char> {
+	// The range [start, end)
+	pub fn new(start: Char, end: Char) -> CharRange<Char> {
+		CharRange { start, end: Bound::Value(end) }
+	}
+
+	// The range containing every value from start through the last
+	// representable Char
+	pub fn through_max(start: Char) -> CharRange<Char> {
+		CharRange { start, end: Bound::PastMax }
+	}
+
+	// The range containing only char
+	pub fn single(char: Char) -> CharRange<Char>
+	where
+		Char: Step,
+	{
+		let end = char.succ();
+		CharRange { start: char, end }
+	}
+
+	pub fn contains(&self, char: &Char) -> bool {
+		self.start <= *char && Bound::Value(char.clone()) < self.end
+	}
+}
+
+// Split a collection of possibly-overlapping ranges into the coarsest set of
+// disjoint ranges that refines all of them, i.e. every input range is the
+// union of some contiguous run of the output ranges
+pub fn common_refinement<Char: Ord + Clone>(
+	ranges: impl Iterator<Item = CharRange<Char>>,
+) -> Vec<CharRange<Char>> {
+	let mut boundaries : BTreeSet<Bound<Char>> = BTreeSet::new();
+	for range in ranges {
+		boundaries.insert(Bound::Value(range.start));
+		boundaries.insert(range.end);
+	}
+	let sorted : Vec<Bound<Char>> = boundaries.into_iter().collect();
+	sorted
+		.windows(2)
+		.map(|pair| {
+			let start = match &pair[0] {
+				Bound::Value(char) => char.clone(),
+				// Nothing sorts after PastMax, so it can only ever appear as
+				// the last boundary, never paired as a window's start
+				Bound::PastMax => unreachable!("PastMax is always the last boundary"),
+			};
+			CharRange { start, end: pair[1].clone() }
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn succ_crosses_surrogate_gap() {
+		assert_eq!('\u{D7FF}'.succ(), Bound::Value('\u{E000}'));
+	}
+
+	#[test]
+	fn succ_reports_past_max_at_the_last_char() {
+		assert_eq!(char::MAX.succ(), Bound::PastMax);
+	}
+
+	#[test]
+	fn single_does_not_panic_below_surrogate_gap() {
+		// '\u{D7FF}' is an ordinary valid char; building a single-char range
+		// around it must not panic trying to step into the surrogate gap
+		let range = CharRange::single('\u{D7FF}');
+		assert!(range.contains(&'\u{D7FF}'));
+		assert!(!range.contains(&'\u{E000}'));
+	}
+
+	#[test]
+	fn single_does_not_panic_at_char_max() {
+		// char::MAX has no successor, so single() must not panic building a
+		// range around it, and that range must still only contain char::MAX
+		let range = CharRange::single(char::MAX);
+		assert!(range.contains(&char::MAX));
+		assert!(!range.contains(&'a'));
+	}
+
+	#[test]
+	fn through_max_contains_every_char_from_start() {
+		let range = CharRange::through_max('a');
+		assert!(range.contains(&'a'));
+		assert!(range.contains(&char::MAX));
+		assert!(!range.contains(&'\0'));
+	}
+
+	#[test]
+	fn common_refinement_splits_overlapping_ranges() {
+		let a = CharRange::new('a', 'n');
+		let b = CharRange::new('g', 'z');
+		let refinement = common_refinement(vec![a, b].into_iter());
+		// The refinement must partition the union of both ranges, and every
+		// input range must be a contiguous union of output ranges, so 'c'
+		// (inside only a), 'h' and 'm' (inside both inputs) and 'p' (inside
+		// only b) are each covered by exactly one output range
+		let covering = |c: char| refinement.iter().filter(|r| r.contains(&c)).count();
+		assert_eq!(covering('c'), 1);
+		assert_eq!(covering('h'), 1);
+		assert_eq!(covering('p'), 1);
+		assert_eq!(refinement.iter().map(|r| r.contains(&'m')).filter(|b| *b).count(), 1);
+	}
+
+	#[test]
+	fn common_refinement_handles_a_through_max_range() {
+		let refinement = common_refinement(vec![CharRange::through_max('a')].into_iter());
+		assert_eq!(refinement.len(), 1);
+		assert!(refinement[0].contains(&char::MAX));
+	}
+}
@@ -1,17 +1,29 @@
+use crate::range::{common_refinement, CharRange};
+
+// Identifies a capture group; distinct Capture nodes should use distinct ids
+pub type GroupId = u64;
+
+#[derive(Clone)]
 pub enum Regex<Char> {
 	Empty,
 	Epsilon,
 	Character(Char),
+	// Matches any single character in a half-open range; lets a class like
+	// [a-z0-9] reach the Nfa as a handful of ranged transitions instead of
+	// one Character per member, the way Hir::Class lowers it
+	Range(CharRange<Char>),
 	Concat(Box<Regex<Char>>, Box<Regex<Char>>),
 	Union(Box<Regex<Char>>, Box<Regex<Char>>),
 	Star(Box<Regex<Char>>),
+	// Marks the span matched by the contained regex as capture group id
+	Capture(GroupId, Box<Regex<Char>>),
 }
 
 use Regex::*;
 
 impl<Char> Regex<Char>
 where
-	Char: Eq,
+	Char: Ord + Clone,
 {
 	pub fn parse_string(&self, string: &[Char]) -> bool {
 		match self {
@@ -21,6 +33,9 @@ where
 				string.iter().all(|c| *c == *char) &&
 				string.len() == 1
 			},
+			Range(range) => {
+				string.len() == 1 && range.contains(&string[0])
+			},
 			Concat(left, right) => {
 				for i in 0..string.len()+1 {
 					if
@@ -42,6 +57,7 @@ where
 				}
 				false
 			}
+			Capture(_, contents) => { contents.parse_string(string) }
 
 		}
 	}
@@ -49,4 +65,175 @@ where
 	// fn parse_regex(string: &str) -> Regex<char> {
 
 	// }
+}
+
+// A richer syntax tree, closer to what a regex parser would hand back (c.f.
+// regex_syntax::hir::Hir), that lowers down to the primitive Regex
+// operations above. This is what lets users write character classes and
+// bounded repetition without assembling a Regex tree by hand.
+pub enum Hir<Char> {
+	Empty,
+	Epsilon,
+	Literal(Char),
+	// The ranges may overlap; lower() refines them before lowering, so a
+	// class like [a-z0-9] becomes a handful of Regex::Range unions rather
+	// than enumerating every member character
+	Class(Vec<CharRange<Char>>),
+	Concat(Box<Hir<Char>>, Box<Hir<Char>>),
+	Union(Box<Hir<Char>>, Box<Hir<Char>>),
+	Star(Box<Hir<Char>>),
+	Plus(Box<Hir<Char>>),
+	// Repetition(contents, min, max), max = None means unbounded
+	Repetition(Box<Hir<Char>>, u32, Option<u32>),
+}
+
+impl<Char> Hir<Char>
+where
+	Char: Ord + Clone,
+{
+	pub fn lower(&self) -> Regex<Char> {
+		match self {
+			Hir::Empty => Empty,
+			Hir::Epsilon => Epsilon,
+			Hir::Literal(char) => Character(char.clone()),
+			// A class is the union of its member ranges, refined into
+			// disjoint ranges first so overlapping members aren't matched twice
+			Hir::Class(members) => {
+				common_refinement(members.iter().cloned())
+					.into_iter()
+					.map(Range)
+					.reduce(|left, right| Union(Box::new(left), Box::new(right)))
+					.unwrap_or(Empty)
+			}
+			Hir::Concat(left, right) => Concat(Box::new(left.lower()), Box::new(right.lower())),
+			Hir::Union(left, right) => Union(Box::new(left.lower()), Box::new(right.lower())),
+			Hir::Star(contents) => Star(Box::new(contents.lower())),
+			// a+ is a concatenated with a*
+			Hir::Plus(contents) => {
+				let inner = contents.lower();
+				Concat(Box::new(inner.clone()), Box::new(Star(Box::new(inner))))
+			}
+			Hir::Repetition(contents, min, max) => {
+				let inner = contents.lower();
+				let required = repeat_exact(&inner, *min);
+				match max {
+					// a{n,} is a^n concatenated with a*
+					None => Concat(Box::new(required), Box::new(Star(Box::new(inner)))),
+					// a{n,m} is a^n concatenated with (a?)^(m-n), and
+					// a{n} is just the n == m case of that
+					Some(max) => {
+						let optional = Union(Box::new(inner), Box::new(Epsilon));
+						Concat(Box::new(required), Box::new(repeat_exact(&optional, max.saturating_sub(*min))))
+					}
+				}
+			}
+		}
+	}
+}
+
+// Concatenate n copies of a Regex together, Epsilon if n is 0
+fn repeat_exact<Char: Clone>(regex: &Regex<Char>, n: u32) -> Regex<Char> {
+	let mut result = Epsilon;
+	for _ in 0..n {
+		result = Concat(Box::new(result), Box::new(regex.clone()));
+	}
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::conversions::regex_to_dfa;
+
+	fn universe() -> CharRange<char> {
+		CharRange::new('a', '{')
+	}
+
+	// Regex::parse_string's own Star arm never accepts the
+	// zero-repetition case for non-nullable contents (a pre-existing,
+	// unrelated bug), so Plus/Repetition lowering - which always goes
+	// through a Star - is checked via a DFA built from the lowered regex
+	// instead of parse_string directly
+	fn accepts(regex: &Regex<char>, string: &[char]) -> bool {
+		let dfa = regex_to_dfa(regex, universe());
+		let mut state = &dfa.start_state;
+		for char in string {
+			let ranges = dfa.transitions.get(state).expect("Invalid DFA");
+			state = ranges
+				.iter()
+				.find(|(range, _)| range.contains(char))
+				.map(|(_, target)| target)
+				.expect("No transition for character");
+		}
+		dfa.accepting.contains(state)
+	}
+
+	#[test]
+	fn plus_requires_at_least_one_repetition() {
+		let lowered = Hir::Plus(Box::new(Hir::Literal('a'))).lower();
+		assert!(!accepts(&lowered, &[]));
+		assert!(accepts(&lowered, &['a']));
+		assert!(accepts(&lowered, &['a', 'a', 'a']));
+	}
+
+	#[test]
+	fn repetition_exact_count_only_matches_that_many() {
+		let lowered = Hir::Repetition(Box::new(Hir::Literal('a')), 2, Some(2)).lower();
+		assert!(!accepts(&lowered, &['a']));
+		assert!(accepts(&lowered, &['a', 'a']));
+		assert!(!accepts(&lowered, &['a', 'a', 'a']));
+	}
+
+	#[test]
+	fn repetition_bounded_range_matches_between_min_and_max() {
+		let lowered = Hir::Repetition(Box::new(Hir::Literal('a')), 1, Some(2)).lower();
+		assert!(!accepts(&lowered, &[]));
+		assert!(accepts(&lowered, &['a']));
+		assert!(accepts(&lowered, &['a', 'a']));
+		assert!(!accepts(&lowered, &['a', 'a', 'a']));
+	}
+
+	#[test]
+	fn repetition_unbounded_min_matches_at_least_min() {
+		let lowered = Hir::Repetition(Box::new(Hir::Literal('a')), 1, None).lower();
+		assert!(!accepts(&lowered, &[]));
+		assert!(accepts(&lowered, &['a']));
+		assert!(accepts(&lowered, &['a', 'a', 'a']));
+	}
+
+	// Count the Regex::Range/Regex::Character nodes a lowered Hir::Class
+	// produces; used to confirm a wide class lowers into a handful of ranges
+	// rather than enumerating one node per member character
+	fn count_members(regex: &Regex<char>) -> usize {
+		match regex {
+			Character(_) | Range(_) => 1,
+			Union(left, right) => count_members(left) + count_members(right),
+			_ => 0,
+		}
+	}
+
+	// Regression test for the chunk1-2 alphabet-enumeration-blowup bug: a
+	// wide class used to lower into one Regex::Character per member, which
+	// for something like the whole Unicode range explodes into ~1.1M nodes.
+	// It must lower into one Regex::Range per disjoint refined range instead.
+	#[test]
+	fn wide_class_lowers_into_a_handful_of_ranges() {
+		let class = Hir::Class(vec![CharRange::new('a', '{')]);
+		let lowered = class.lower();
+		assert_eq!(count_members(&lowered), 1);
+		assert!(matches!(lowered, Range(_)));
+	}
+
+	#[test]
+	fn overlapping_class_members_are_refined_before_lowering() {
+		let class = Hir::Class(vec![
+			CharRange::new('a', 'n'),
+			CharRange::new('g', 'z'),
+			]);
+		let lowered = class.lower();
+		assert!(lowered.parse_string(&['a']));
+		assert!(lowered.parse_string(&['m']));
+		assert!(lowered.parse_string(&['y']));
+		assert!(!lowered.parse_string(&['z']));
+	}
 }
\ No newline at end of file